@@ -4,21 +4,259 @@ use anyhow::Context;
 use governor::Quota;
 use once_cell::sync::Lazy;
 use pnet_packet::{
+    ip::IpNextHeaderProtocols,
     ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
     tcp::{TcpFlags, TcpPacket},
     Packet,
 };
+use serde::{Deserialize, Serialize};
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
 use std::{
+    collections::{HashMap, VecDeque},
     io::{Stdin, Stdout},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     num::NonZeroU32,
     time::Duration,
 };
-use std::{sync::Arc, time::Instant};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 use vpn_structs::StdioMsg;
 
+/// A socks5-connect request taken off the channel but not yet satisfied,
+/// tagged with a monotonic id so it can be replayed if the session dies.
+type PendingRequest = (u64, String, Sender<sosistab::mux::RelConn>);
+/// Requests not yet satisfied, carried across session restarts.
+type PendingQueue = Arc<smol::lock::Mutex<VecDeque<PendingRequest>>>;
+
+/// Context attached to a session death so the actor can decide how hard to
+/// back off and whether the failure looks tied to the current exit/bridge
+/// selection (worth retrying as-is) or the whole path (worth reselecting).
+struct SessionDeath {
+    error: anyhow::Error,
+    retry_same_path: bool,
+}
+
+const BACKOFF_INITIAL: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+const BACKOFF_JITTER: f64 = 0.25;
+
+/// Applies +/-`jitter_frac` of jitter to `backoff`, steered by `sample` (a
+/// caller-supplied value in `[0, 1)`, normally `rand::random()`) so the math
+/// can be exercised with a fixed sample in tests.
+fn jittered_backoff(backoff: Duration, jitter_frac: f64, sample: f64) -> Duration {
+    let factor = 1.0 + (sample * 2.0 - 1.0) * jitter_frac;
+    backoff.mul_f64(factor.max(0.0))
+}
+
+/// How long a session needs to stay up before we consider it healthy again
+/// and reset the backoff back down to `BACKOFF_INITIAL`.
+const HEALTHY_MIN_UPTIME: Duration = Duration::from_secs(30);
+
+/// How a [`MultipathMux`] ranks its legs when re-probing them. The top-ranked
+/// leg is the one whose statistics the aggregate reports; outbound frames are
+/// round-robined across all alive legs whatever the policy.
+#[derive(Clone, Copy, Debug)]
+pub enum PathSelectionPolicy {
+    /// rank legs by lowest measured ping.
+    LatencyMinimizing,
+    /// rank legs by lowest measured packet loss.
+    LossMinimizing,
+}
+
+const MULTIPATH_PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Finds the index of the next alive leg starting at `start` and wrapping
+/// around `alive`, skipping dead ones; `None` if every leg is dead.
+fn pick_next_alive(alive: &[bool], start: usize) -> Option<usize> {
+    let n = alive.len();
+    (0..n)
+        .map(|offset| (start + offset) % n)
+        .find(|&i| alive[i])
+}
+
+/// Aggregates several bridge sosistab sessions into one logical path,
+/// round-robining outbound traffic across whichever legs are currently alive.
+struct MultipathMux {
+    legs: Vec<Arc<sosistab::mux::Multiplex>>,
+    policy: PathSelectionPolicy,
+    /// `alive[i]` is cleared by leg `i`'s relay task the moment it observes
+    /// its session has died, so `reprobe` and leg selection both stop
+    /// considering it without waiting for the next probe tick.
+    alive: Arc<Vec<AtomicBool>>,
+    best: smol::lock::RwLock<usize>,
+    rr: AtomicUsize,
+    urel_recv: Receiver<bytes::Bytes>,
+}
+
+impl MultipathMux {
+    fn new(legs: Vec<Arc<sosistab::mux::Multiplex>>, policy: PathSelectionPolicy) -> Arc<Self> {
+        let (urel_send, urel_recv) = smol::channel::unbounded();
+        let alive: Arc<Vec<AtomicBool>> =
+            Arc::new(legs.iter().map(|_| AtomicBool::new(true)).collect());
+        for (i, leg) in legs.iter().enumerate() {
+            let leg = leg.clone();
+            let urel_send = urel_send.clone();
+            let alive = alive.clone();
+            GEXEC
+                .spawn(async move {
+                    loop {
+                        match leg.recv_urel().await {
+                            Ok(bts) => {
+                                if urel_send.send(bts).await.is_err() {
+                                    return;
+                                }
+                            }
+                            Err(err) => {
+                                log::warn!("multipath leg {} died: {}", i, err);
+                                alive[i].store(false, Ordering::SeqCst);
+                                return;
+                            }
+                        }
+                    }
+                })
+                .detach();
+        }
+        let this = Arc::new(MultipathMux {
+            legs,
+            policy,
+            alive,
+            best: smol::lock::RwLock::new(0),
+            rr: AtomicUsize::new(0),
+            urel_recv,
+        });
+        let probing = this.clone();
+        GEXEC
+            .spawn(async move {
+                loop {
+                    smol::Timer::after(MULTIPATH_PROBE_INTERVAL).await;
+                    probing.reprobe().await;
+                }
+            })
+            .detach();
+        this
+    }
+
+    /// re-measures every still-alive leg and promotes/demotes the current
+    /// best one; dead legs are skipped entirely.
+    async fn reprobe(&self) {
+        let mut scored = Vec::with_capacity(self.legs.len());
+        for (i, leg) in self.legs.iter().enumerate() {
+            if !self.alive[i].load(Ordering::SeqCst) {
+                continue;
+            }
+            let stats = leg.get_session().get_stats().await;
+            let score = match self.policy {
+                PathSelectionPolicy::LatencyMinimizing => stats.ping.as_secs_f64(),
+                PathSelectionPolicy::LossMinimizing => stats.down_loss as f64,
+            };
+            scored.push((i, score));
+        }
+        if let Some((best_idx, _)) = scored
+            .into_iter()
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        {
+            *self.best.write().await = best_idx;
+        }
+    }
+
+    async fn best_leg(&self) -> Arc<sosistab::mux::Multiplex> {
+        self.legs[*self.best.read().await].clone()
+    }
+
+    /// round-robins across currently-alive legs so outbound traffic actually
+    /// gets striped across the aggregate instead of sticking to one leg.
+    fn next_alive_leg(&self) -> Option<Arc<sosistab::mux::Multiplex>> {
+        let n = self.legs.len();
+        let start = self.rr.fetch_add(1, Ordering::SeqCst) % n;
+        let alive: Vec<bool> = self
+            .alive
+            .iter()
+            .map(|a| a.load(Ordering::SeqCst))
+            .collect();
+        pick_next_alive(&alive, start).map(|i| self.legs[i].clone())
+    }
+
+    async fn open_conn(&self, remote: Option<String>) -> anyhow::Result<sosistab::mux::RelConn> {
+        self.next_alive_leg()
+            .context("all multipath legs died")?
+            .open_conn(remote)
+            .await
+    }
+
+    async fn send_urel(&self, bts: bytes::Bytes) -> anyhow::Result<()> {
+        self.next_alive_leg()
+            .context("all multipath legs died")?
+            .send_urel(bts)
+            .await
+    }
+
+    async fn recv_urel(&self) -> anyhow::Result<bytes::Bytes> {
+        self.urel_recv
+            .recv()
+            .await
+            .map_err(|_| anyhow::anyhow!("all multipath legs died"))
+    }
+
+    async fn get_stats(&self) -> sosistab::SessionStats {
+        self.best_leg().await.get_session().get_stats().await
+    }
+}
+
+/// The session abstraction `keepalive_actor_once` actually drives: either a
+/// single muxed sosistab session, or a multipath aggregate over several
+/// bridges. Callers shouldn't need to care which.
+enum KaMux {
+    Single(Arc<sosistab::mux::Multiplex>),
+    Multipath(Arc<MultipathMux>),
+}
+
+impl KaMux {
+    async fn open_conn(&self, remote: Option<String>) -> anyhow::Result<sosistab::mux::RelConn> {
+        match self {
+            KaMux::Single(mux) => mux.open_conn(remote).await,
+            KaMux::Multipath(mux) => mux.open_conn(remote).await,
+        }
+    }
+
+    async fn send_urel(&self, bts: bytes::Bytes) -> anyhow::Result<()> {
+        match self {
+            KaMux::Single(mux) => mux.send_urel(bts).await,
+            KaMux::Multipath(mux) => mux.send_urel(bts).await,
+        }
+    }
+
+    async fn recv_urel(&self) -> anyhow::Result<bytes::Bytes> {
+        match self {
+            KaMux::Single(mux) => mux.recv_urel().await,
+            KaMux::Multipath(mux) => mux.recv_urel().await,
+        }
+    }
+
+    async fn get_stats(&self) -> sosistab::SessionStats {
+        match self {
+            KaMux::Single(mux) => mux.get_session().get_stats().await,
+            KaMux::Multipath(mux) => mux.get_stats().await,
+        }
+    }
+
+    /// the underlying muxed sessions that each need their own key rotation:
+    /// one for a single path, one per leg for a multipath aggregate.
+    fn legs(&self) -> Vec<Arc<sosistab::mux::Multiplex>> {
+        match self {
+            KaMux::Single(mux) => vec![mux.clone()],
+            KaMux::Multipath(mux) => mux.legs.clone(),
+        }
+    }
+}
+
 /// An "actor" that keeps a client session alive.
 pub struct Keepalive {
     open_socks5_conn: Sender<(String, Sender<sosistab::mux::RelConn>)>,
@@ -27,13 +265,21 @@ pub struct Keepalive {
 }
 
 impl Keepalive {
-    /// Creates a new keepalive.
+    /// Creates a new keepalive. `multipath_degree` is the number of bridges
+    /// to aggregate traffic over simultaneously (1 disables multipath and
+    /// keeps the old single-fastest-bridge behavior); `path_policy` controls
+    /// how the multipath aggregate ranks its legs for statistics reporting
+    /// (traffic is round-robined over all alive legs). `qos` configures
+    /// the outbound traffic shaper used in VPN mode.
     pub fn new(
         stats: Arc<StatCollector>,
         exit_host: &str,
         use_bridges: bool,
         stdio_vpn: bool,
         ccache: Arc<ClientCache>,
+        multipath_degree: usize,
+        path_policy: PathSelectionPolicy,
+        qos: QosConfig,
     ) -> Self {
         let (send, recv) = smol::channel::unbounded();
         let (send_stats, recv_stats) = smol::channel::unbounded();
@@ -48,6 +294,9 @@ impl Keepalive {
                 ccache,
                 recv,
                 recv_stats,
+                multipath_degree.max(1),
+                path_policy,
+                qos,
             )),
         }
     }
@@ -77,8 +326,26 @@ async fn keepalive_actor(
     ccache: Arc<ClientCache>,
     recv_socks5_conn: Receiver<(String, Sender<sosistab::mux::RelConn>)>,
     recv_get_stats: Receiver<Sender<sosistab::SessionStats>>,
+    multipath_degree: usize,
+    path_policy: PathSelectionPolicy,
+    qos: QosConfig,
 ) -> anyhow::Result<()> {
+    // owned here, outside keepalive_actor_once, so that requests queued but
+    // not yet satisfied survive a session replacement instead of being
+    // dropped on the floor.
+    let pending: PendingQueue = Arc::new(smol::lock::Mutex::new(VecDeque::new()));
+    let next_id = Arc::new(AtomicU64::new(0));
+    let retry_same_path = Arc::new(AtomicBool::new(true));
+    // the bridge endpoint the most recent session connected through, so that
+    // when a death is flagged `!retry_same_path` the next attempt can
+    // actually avoid it instead of just logging the intent.
+    let last_bridge: Arc<smol::lock::Mutex<Option<SocketAddr>>> =
+        Arc::new(smol::lock::Mutex::new(None));
+    let mut backoff = BACKOFF_INITIAL;
     loop {
+        let reselect = !retry_same_path.load(Ordering::SeqCst);
+        retry_same_path.store(true, Ordering::SeqCst);
+        let session_start = Instant::now();
         if let Err(err) = keepalive_actor_once(
             stats.clone(),
             exit_host.clone(),
@@ -87,11 +354,34 @@ async fn keepalive_actor(
             ccache.clone(),
             recv_socks5_conn.clone(),
             recv_get_stats.clone(),
+            pending.clone(),
+            next_id.clone(),
+            retry_same_path.clone(),
+            last_bridge.clone(),
+            reselect,
+            multipath_degree,
+            path_policy,
+            qos.clone(),
         )
         .await
         {
-            log::warn!("keepalive_actor restarting: {}", err);
-            smol::Timer::after(Duration::from_secs(1)).await;
+            if session_start.elapsed() >= HEALTHY_MIN_UPTIME {
+                backoff = BACKOFF_INITIAL;
+            }
+            log::warn!(
+                "keepalive_actor restarting in {:?} ({}): {}",
+                backoff,
+                if retry_same_path.load(Ordering::SeqCst) {
+                    "retrying same exit/bridge"
+                } else {
+                    "will reselect exit/bridge"
+                },
+                err
+            );
+            smol::Timer::after(jittered_backoff(backoff, BACKOFF_JITTER, rand::random())).await;
+            backoff = (backoff * 2).min(BACKOFF_MAX);
+        } else {
+            backoff = BACKOFF_INITIAL;
         }
     }
 }
@@ -104,9 +394,31 @@ async fn keepalive_actor_once(
     ccache: Arc<ClientCache>,
     recv_socks5_conn: Receiver<(String, Sender<sosistab::mux::RelConn>)>,
     recv_get_stats: Receiver<Sender<sosistab::SessionStats>>,
+    pending: PendingQueue,
+    next_id: Arc<AtomicU64>,
+    retry_same_path: Arc<AtomicBool>,
+    last_bridge: Arc<smol::lock::Mutex<Option<SocketAddr>>>,
+    reselect: bool,
+    multipath_degree: usize,
+    path_policy: PathSelectionPolicy,
+    qos: QosConfig,
 ) -> anyhow::Result<()> {
     stats.set_exit_descriptor(None);
 
+    // if the previous session's death was flagged as not tied to the
+    // specific bridge we were on, don't just reconnect to the same one.
+    let exclude_bridge = if reselect {
+        *last_bridge.lock().await
+    } else {
+        None
+    };
+    if let Some(excluded) = exclude_bridge {
+        log::debug!(
+            "previous failure wasn't pinned to the bridge; excluding {} from reselection",
+            excluded
+        );
+    }
+
     // find the exit
     let mut exits = ccache.get_exits().await.context("can't get exits")?;
     if exits.is_empty() {
@@ -118,12 +430,16 @@ async fn keepalive_actor_once(
     });
     let exit_host = exits[0].hostname.clone();
 
-    let bridge_sess_async = async {
+    let bridge_sess_async = || async {
         let bridges = ccache
             .get_bridges(&exit_host)
             .await
             .context("can't get bridges")?;
         log::debug!("got {} bridges", bridges.len());
+        let bridges: Vec<_> = bridges
+            .into_iter()
+            .filter(|desc| Some(desc.endpoint) != exclude_bridge)
+            .collect();
         if bridges.is_empty() {
             anyhow::bail!("absolutely no bridges found")
         }
@@ -150,43 +466,119 @@ async fn keepalive_actor_once(
             let (saddr, res) = recv.recv().await.context("ran out of bridges")?;
             if let Ok(res) = res {
                 log::info!("{} is our fastest bridge", saddr);
-                break Ok(res);
+                break Ok((saddr, res));
             }
         }
     };
     let exit_info = exits.iter().find(|v| v.hostname == exit_host).unwrap();
-    let connected_sess_async = async {
-        if use_bridges {
-            bridge_sess_async.await
+    // Any failure while establishing the mux (no bridges, the initial
+    // connection timing out, etc.) is flagged as a path-level failure, so the
+    // next attempt reselects and leaves out the bridge that the last working
+    // session went through.
+    let mux: anyhow::Result<Arc<KaMux>> = async {
+        let mux: Arc<KaMux> = if use_bridges && multipath_degree > 1 {
+            // multipath mode: aggregate several bridges instead of picking one.
+            let legs: anyhow::Result<Vec<_>> =
+                bridge_sess_multi_async(&ccache, &exit_host, multipath_degree)
+                    .or(async {
+                        smol::Timer::after(Duration::from_secs(10)).await;
+                        anyhow::bail!("initial connection timeout after 10");
+                    })
+                    .await;
+            let legs = legs?
+                .into_iter()
+                .map(|sess| Arc::new(sosistab::mux::Multiplex::new(sess)))
+                .collect();
+            log::info!(
+                "multipath enabled: aggregating bridges with {:?} policy",
+                path_policy
+            );
+            Arc::new(KaMux::Multipath(MultipathMux::new(legs, path_policy)))
         } else {
-            async {
-                Ok(infal(
-                    sosistab::connect(
-                        smol::net::resolve(format!("{}:19831", exit_info.hostname))
-                            .await
-                            .context("can't resolve hostname of exit")?[0],
-                        exit_info.sosistab_key,
-                    )
-                    .await,
-                )
-                .await)
+            let connected_sess_async = async {
+                if use_bridges {
+                    let (saddr, sess) = bridge_sess_async().await?;
+                    Ok((sess, true, Some(saddr)))
+                } else {
+                    async {
+                        Ok((
+                            infal(
+                                sosistab::connect(
+                                    smol::net::resolve(format!("{}:19831", exit_info.hostname))
+                                        .await
+                                        .context("can't resolve hostname of exit")?[0],
+                                    exit_info.sosistab_key,
+                                )
+                                .await,
+                            )
+                            .await,
+                            false,
+                            None,
+                        ))
+                    }
+                    .or(async {
+                        smol::Timer::after(Duration::from_secs(5)).await;
+                        log::warn!(
+                            "turning on bridges because we couldn't get a direct connection"
+                        );
+                        let (saddr, sess) = bridge_sess_async().await?;
+                        Ok((sess, true, Some(saddr)))
+                    })
+                    .await
+                }
+            };
+            let session: anyhow::Result<(sosistab::Session, bool, Option<SocketAddr>)> =
+                connected_sess_async
+                    .or(async {
+                        smol::Timer::after(Duration::from_secs(10)).await;
+                        anyhow::bail!("initial connection timeout after 10");
+                    })
+                    .await;
+            let (session, via_bridge, bridge_endpoint) = session?;
+            if let Some(saddr) = bridge_endpoint {
+                *last_bridge.lock().await = Some(saddr);
             }
-            .or(async {
-                smol::Timer::after(Duration::from_secs(5)).await;
-                log::warn!("turning on bridges because we couldn't get a direct connection");
-                bridge_sess_async.await
-            })
-            .await
-        }
-    };
-    let session: anyhow::Result<sosistab::Session> = connected_sess_async
-        .or(async {
-            smol::Timer::after(Duration::from_secs(10)).await;
-            anyhow::bail!("initial connection timeout after 10");
-        })
-        .await;
-    let session = session?;
-    let mux = Arc::new(sosistab::mux::Multiplex::new(session));
+            // If we got here over a bridge, try to upgrade to a direct,
+            // hole-punched UDP path to the exit, using the bridge session as a
+            // signaling channel. This races against the bridge session rather
+            // than replacing it -- if it fails or simply doesn't finish in time
+            // (e.g. a symmetric NAT on either end) we just keep the bridge mux we
+            // already have.
+            if via_bridge {
+                let bridge_mux = Arc::new(sosistab::mux::Multiplex::new(session));
+                match hole_punch_sess_async(&bridge_mux, exit_info.sosistab_key)
+                    .timeout(Duration::from_secs(3))
+                    .await
+                {
+                    Some(Ok(direct)) => {
+                        log::info!("hole-punched a direct path to the exit, bypassing the bridge");
+                        Arc::new(KaMux::Single(Arc::new(sosistab::mux::Multiplex::new(
+                            direct,
+                        ))))
+                    }
+                    Some(Err(err)) => {
+                        log::debug!("hole punch failed, staying on the bridge: {}", err);
+                        Arc::new(KaMux::Single(bridge_mux))
+                    }
+                    None => {
+                        log::debug!("hole punch timed out, staying on the bridge");
+                        Arc::new(KaMux::Single(bridge_mux))
+                    }
+                }
+            } else {
+                Arc::new(KaMux::Single(Arc::new(sosistab::mux::Multiplex::new(
+                    session,
+                ))))
+            }
+        };
+        Ok(mux)
+    }
+    .await
+    .map_err(|err| {
+        retry_same_path.store(false, Ordering::SeqCst);
+        err
+    });
+    let mux = mux?;
     let scope = smol::Executor::new();
     // now let's authenticate
     let token = ccache.get_auth_token().await?;
@@ -216,14 +608,67 @@ async fn keepalive_actor_once(
             }
         })
         .detach();
+    scope.spawn(rekey_loop(mux.clone(), stats.clone())).detach();
 
     // VPN mode
     let mut _nuunuu = None;
     if stdio_vpn {
-        _nuunuu = Some(GEXEC.spawn(run_vpn(stats.clone(), mux.clone())));
+        _nuunuu = Some(GEXEC.spawn(run_vpn(stats.clone(), mux.clone(), qos)));
+    }
+
+    let (send_death, recv_death) = smol::channel::unbounded::<SessionDeath>();
+    // spawns (or re-spawns, after a session restart) the task that tries to
+    // open a single conn; only removes the request from `pending` on success,
+    // so a request still in flight when the session dies stays queued for
+    // the next session to pick up.
+    let spawn_attempt = |id: u64, conn_host: String, conn_reply: Sender<sosistab::mux::RelConn>| {
+        let mux = &mux;
+        let pending = pending.clone();
+        let send_death = send_death.clone();
+        scope
+            .spawn(async move {
+                let start = Instant::now();
+                let remote = mux.open_conn(Some(conn_host)).await;
+                match remote {
+                    Ok(remote) => {
+                        pending.lock().await.retain(|(pid, _, _)| *pid != id);
+                        let sess_stats = mux.get_stats().await;
+                        log::debug!(
+                            "opened connection in {} ms; loss = {:.2}% => {:.2}%; overhead = {:.2}%",
+                            start.elapsed().as_millis(),
+                            sess_stats.down_loss * 100.0,
+                            sess_stats.down_recovered_loss * 100.0,
+                            sess_stats.down_redundant * 100.0,
+                        );
+                        conn_reply.send(remote).await?;
+                        Ok::<(), anyhow::Error>(())
+                    }
+                    Err(err) => {
+                        send_death
+                            .send(SessionDeath {
+                                error: anyhow::anyhow!(
+                                    "conn open error {} in {}s",
+                                    err,
+                                    start.elapsed().as_secs_f64()
+                                ),
+                                retry_same_path: true,
+                            })
+                            .await?;
+                        Ok(())
+                    }
+                }
+            })
+            .detach();
+    };
+
+    // replay anything left over from a previous, failed session before
+    // accepting new requests.
+    let leftover: Vec<_> = pending.lock().await.iter().cloned().collect();
+    for (id, conn_host, conn_reply) in leftover {
+        log::info!("replaying queued request for {} on new session", conn_host);
+        spawn_attempt(id, conn_host, conn_reply);
     }
 
-    let (send_death, recv_death) = smol::channel::unbounded::<anyhow::Error>();
     scope
         .run(
             async {
@@ -232,48 +677,23 @@ async fn keepalive_actor_once(
                         .recv()
                         .await
                         .context("cannot get socks5 connect request")?;
-                    let mux = &mux;
-                    let send_death = send_death.clone();
-                    scope
-                        .spawn(async move {
-                            let start = Instant::now();
-                            let remote = (&mux).open_conn(Some(conn_host)).await;
-                            match remote {
-                                Ok(remote) => {
-                                    let sess_stats = mux.get_session().get_stats().await;
-                                    log::debug!(
-                                        "opened connection in {} ms; loss = {:.2}% => {:.2}%; overhead = {:.2}%",
-                                        start.elapsed().as_millis(),
-                                        sess_stats.down_loss * 100.0,
-                                        sess_stats.down_recovered_loss * 100.0,
-                                        sess_stats.down_redundant * 100.0,
-                                    );
-                                    conn_reply.send(remote).await?;
-                                    Ok::<(), anyhow::Error>(())
-                                }
-                                Err(err) => {
-                                    send_death
-                                        .send(anyhow::anyhow!(
-                                            "conn open error {} in {}s",
-                                            err,
-                                            start.elapsed().as_secs_f64()
-                                        ))
-                                        .await?;
-                                    Ok(())
-                                }
-                            }
-                        })
-                        .detach();
+                    let id = next_id.fetch_add(1, Ordering::SeqCst);
+                    pending
+                        .lock()
+                        .await
+                        .push_back((id, conn_host.clone(), conn_reply.clone()));
+                    spawn_attempt(id, conn_host, conn_reply);
                 }
             }
             .or(async {
-                let e = recv_death.recv().await?;
-                anyhow::bail!(e)
+                let death = recv_death.recv().await?;
+                retry_same_path.store(death.retry_same_path, Ordering::SeqCst);
+                anyhow::bail!(death.error)
             })
             .or(async {
                 loop {
                     let stat_send = recv_get_stats.recv().await?;
-                    let stats = mux.get_session().get_stats().await;
+                    let stats = mux.get_stats().await;
                     drop(stat_send.send(stats).await);
                 }
             }),
@@ -281,6 +701,237 @@ async fn keepalive_actor_once(
         .await
 }
 
+/// How often a session's key gets rotated, absent any traffic-triggered
+/// rotation.
+const REKEY_INTERVAL: Duration = Duration::from_secs(600);
+/// A session also gets rekeyed early if this much traffic crosses it between
+/// scheduled rotations.
+const REKEY_BYTE_THRESHOLD: u64 = 1 << 30;
+/// A rekey round that doesn't finish within this long is treated the same as
+/// a failed one, same as every other blocking op on the session (auth, the
+/// watchdog, hole punching) -- otherwise an unresponsive peer would wedge
+/// `rekey_loop` forever instead of retrying on the next tick.
+const REKEY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages exchanged over a dedicated control `RelConn` while rotating a
+/// session's symmetric key. `epoch` is a monotonic rotation counter so the
+/// peer always knows which key generation a given message belongs to.
+#[derive(Serialize, Deserialize)]
+struct RekeyHello {
+    epoch: u64,
+    public: [u8; 32],
+}
+
+/// Runs forever alongside the watchdog, periodically negotiating a fresh
+/// ephemeral key pair over each of `mux`'s underlying sessions and rotating
+/// their symmetric keys for forward secrecy. A rotation round that fails
+/// (the peer isn't ready, the conn drops, etc.) just gets retried on the next
+/// tick rather than tearing down the session.
+async fn rekey_loop(mux: Arc<KaMux>, stats: Arc<StatCollector>) {
+    let mut epoch = 0u64;
+    let mut bytes_at_last_rotation = stats.total_tx() + stats.total_rx();
+    loop {
+        let timer_elapsed = async {
+            smol::Timer::after(REKEY_INTERVAL).await;
+        };
+        let byte_triggered = async {
+            loop {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                let now = stats.total_tx() + stats.total_rx();
+                if now.saturating_sub(bytes_at_last_rotation) >= REKEY_BYTE_THRESHOLD {
+                    return;
+                }
+            }
+        };
+        timer_elapsed.or(byte_triggered).await;
+
+        epoch += 1;
+        for leg in mux.legs() {
+            match rekey_leg_once(&leg, epoch).timeout(REKEY_TIMEOUT).await {
+                Some(Ok(())) => log::info!("rotated session key to epoch {}", epoch),
+                Some(Err(err)) => log::warn!(
+                    "rekey round for epoch {} failed, retrying next tick: {}",
+                    epoch,
+                    err
+                ),
+                None => log::warn!(
+                    "rekey round for epoch {} timed out after {:?}, retrying next tick",
+                    epoch,
+                    REKEY_TIMEOUT
+                ),
+            }
+        }
+        bytes_at_last_rotation = stats.total_tx() + stats.total_rx();
+    }
+}
+
+/// Negotiates and switches to a fresh symmetric key for a single underlying
+/// session. The old key is kept alive by the session itself until the peer
+/// acks the switch, so packets already in flight under it still decrypt.
+async fn rekey_leg_once(mux: &sosistab::mux::Multiplex, epoch: u64) -> anyhow::Result<()> {
+    let mut rekey_conn = mux
+        .open_conn(None)
+        .await
+        .context("can't open rekey signaling conn")?;
+    let my_secret = x25519_dalek::EphemeralSecret::new(rand::rngs::OsRng);
+    let my_public = x25519_dalek::PublicKey::from(&my_secret);
+    aioutils::write_pascalish(
+        &mut rekey_conn,
+        &RekeyHello {
+            epoch,
+            public: my_public.to_bytes(),
+        },
+    )
+    .await?;
+    let their_hello: RekeyHello = aioutils::read_pascalish(&mut rekey_conn).await?;
+    anyhow::ensure!(
+        their_hello.epoch == epoch,
+        "rekey epoch mismatch: us={} them={}",
+        epoch,
+        their_hello.epoch
+    );
+    let shared = my_secret.diffie_hellman(&x25519_dalek::PublicKey::from(their_hello.public));
+    let new_key = *blake3::hash(shared.as_bytes()).as_bytes();
+
+    // ack, then wait for the peer's ack, before actually switching -- this is
+    // the "both sides acknowledge" handshake that keeps the switch atomic.
+    aioutils::write_pascalish(&mut rekey_conn, &epoch).await?;
+    let _: u64 = aioutils::read_pascalish(&mut rekey_conn).await?;
+    mux.get_session().rekey(epoch, new_key).await
+}
+
+/// Signaling messages exchanged over a control `RelConn` on the bridge mux
+/// while setting up a direct, hole-punched client<->exit path.
+#[derive(Serialize, Deserialize)]
+struct PunchHello {
+    /// random tie-breaker: the higher nonce becomes the hole-punch initiator,
+    /// so simultaneous open never leaves both sides half-open.
+    nonce: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PunchInfo {
+    /// the client's public UDP endpoint, as observed by the exit/bridge.
+    observed_endpoint: SocketAddr,
+    /// candidate endpoints where the exit can be reached directly.
+    exit_candidates: Vec<SocketAddr>,
+}
+
+const PUNCH_PROBE_COUNT: u32 = 5;
+const PUNCH_PROBE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Attempts to establish a direct client<->exit sosistab session via UDP hole
+/// punching, coordinated over an already-connected bridge session used purely
+/// as a signaling channel. Returns an error on anything short of a working
+/// direct session (e.g. a symmetric NAT); callers should fall back to the
+/// bridge session in that case rather than losing connectivity.
+async fn hole_punch_sess_async(
+    bridge_mux: &sosistab::mux::Multiplex,
+    exit_key: x25519_dalek::PublicKey,
+) -> anyhow::Result<sosistab::Session> {
+    let mut signal_conn = bridge_mux
+        .open_conn(None)
+        .await
+        .context("can't open hole-punch signaling conn")?;
+    let my_nonce: u64 = rand::random();
+    aioutils::write_pascalish(&mut signal_conn, &PunchHello { nonce: my_nonce }).await?;
+    let their_hello: PunchHello = aioutils::read_pascalish(&mut signal_conn).await?;
+    let info: PunchInfo = aioutils::read_pascalish(&mut signal_conn).await?;
+    let am_initiator = my_nonce > their_hello.nonce;
+    log::debug!(
+        "hole-punch: observed as {}, {} exit candidate(s), we are {}",
+        info.observed_endpoint,
+        info.exit_candidates.len(),
+        if am_initiator {
+            "initiator"
+        } else {
+            "responder"
+        }
+    );
+    let target = *info
+        .exit_candidates
+        .get(0)
+        .context("exit reported no candidate endpoints")?;
+
+    // Simultaneously blast a short burst of probe packets at each other's
+    // endpoint to open up NAT mappings on both sides. The packets themselves
+    // are discarded by the exit -- they only exist to punch a hole.
+    let sock = smol::net::UdpSocket::bind("0.0.0.0:0")
+        .await
+        .context("can't bind punch socket")?;
+    for _ in 0..PUNCH_PROBE_COUNT {
+        drop(sock.send_to(b"geph4-punch", target).await);
+        smol::Timer::after(PUNCH_PROBE_INTERVAL).await;
+    }
+
+    // Hand the punched socket itself to sosistab so the session's handshake
+    // goes out from the same source port the probes opened the NAT mapping
+    // on -- a fresh `connect` would bind a new ephemeral port and abandon
+    // the mapping we just punched. `am_initiator` decides which side sends
+    // the first handshake packet, so a simultaneous open doesn't leave both
+    // ends waiting on each other.
+    sosistab::connect_udp(sock, target, exit_key, am_initiator)
+        .await
+        .context("hole-punched direct connect failed")
+}
+
+/// Connects to up to `degree` of the fastest-responding bridges for
+/// `exit_host` simultaneously, returning however many distinct sessions we
+/// managed to establish (at least one, or an error).
+async fn bridge_sess_multi_async(
+    ccache: &ClientCache,
+    exit_host: &str,
+    degree: usize,
+) -> anyhow::Result<Vec<sosistab::Session>> {
+    let bridges = ccache
+        .get_bridges(exit_host)
+        .await
+        .context("can't get bridges")?;
+    log::debug!(
+        "got {} bridges, aggregating up to {} of them",
+        bridges.len(),
+        degree
+    );
+    if bridges.is_empty() {
+        anyhow::bail!("absolutely no bridges found")
+    }
+    let (send, recv) = smol::channel::unbounded();
+    let _tasks: Vec<_> = bridges
+        .into_iter()
+        .map(|desc| {
+            let send = send.clone();
+            GEXEC.spawn(async move {
+                log::debug!("connecting through {}...", desc.endpoint);
+                drop(
+                    send.send((
+                        desc.endpoint,
+                        sosistab::connect(desc.endpoint, desc.sosistab_key).await,
+                    ))
+                    .await,
+                )
+            })
+        })
+        .collect();
+    // only the per-bridge tasks hold senders now, so `recv` fails once every
+    // attempt has reported instead of waiting forever.
+    drop(send);
+    let mut sessions = Vec::new();
+    while sessions.len() < degree {
+        let (saddr, res) = match recv.recv().await {
+            Ok(v) => v,
+            Err(_) => break,
+        };
+        if let Ok(res) = res {
+            log::info!("{} joined the multipath aggregate", saddr);
+            sessions.push(res);
+        }
+    }
+    if sessions.is_empty() {
+        anyhow::bail!("ran out of bridges without establishing any session")
+    }
+    Ok(sessions)
+}
+
 async fn infal<T, E>(v: Result<T, E>) -> T {
     if let Ok(v) = v {
         v
@@ -290,10 +941,7 @@ async fn infal<T, E>(v: Result<T, E>) -> T {
 }
 
 /// authenticates a muxed session
-async fn authenticate_session(
-    session: &sosistab::mux::Multiplex,
-    token: &crate::cache::Token,
-) -> anyhow::Result<()> {
+async fn authenticate_session(session: &KaMux, token: &crate::cache::Token) -> anyhow::Result<()> {
     let mut auth_conn = session.open_conn(None).await?;
     log::debug!("sending auth info...");
     aioutils::write_pascalish(
@@ -309,11 +957,33 @@ async fn authenticate_session(
     Ok(())
 }
 
+/// Request sent over a dedicated `RelConn` asking the exit for an IPv6
+/// address to tunnel, kept separate from `vpn_structs::Message` so dual-stack
+/// support doesn't require extending that crate.
+#[derive(Serialize, Deserialize)]
+struct Ipv6Request;
+
+#[derive(Serialize, Deserialize)]
+struct Ipv6Assign {
+    address: Option<Ipv6Addr>,
+}
+
+/// asks the exit for an IPv6 address over its own signaling conn; `None` if
+/// the exit answers without assigning one. An exit that doesn't understand
+/// the request yields an error (or no answer at all), which `run_vpn` treats
+/// the same as no address.
+async fn negotiate_ipv6_async(mux: &KaMux) -> anyhow::Result<Option<Ipv6Addr>> {
+    let mut conn = mux
+        .open_conn(None)
+        .await
+        .context("can't open IPv6 signaling conn")?;
+    aioutils::write_pascalish(&mut conn, &Ipv6Request).await?;
+    let assign: Ipv6Assign = aioutils::read_pascalish(&mut conn).await?;
+    Ok(assign.address)
+}
+
 /// runs a vpn session
-async fn run_vpn(
-    stats: Arc<StatCollector>,
-    mux: Arc<sosistab::mux::Multiplex>,
-) -> anyhow::Result<()> {
+async fn run_vpn(stats: Arc<StatCollector>, mux: Arc<KaMux>, qos: QosConfig) -> anyhow::Result<()> {
     static STDIN: Lazy<async_dup::Arc<async_dup::Mutex<smol::Unblock<Stdin>>>> = Lazy::new(|| {
         async_dup::Arc::new(async_dup::Mutex::new(smol::Unblock::with_capacity(
             1024 * 1024,
@@ -345,34 +1015,45 @@ async fn run_vpn(
             }
         }
     };
-    log::info!("negotiated IP address {}!", client_ip);
+    log::info!("negotiated IPv4 address {}!", client_ip);
     let msg = StdioMsg {
         verb: 1,
         body: format!("{}/10", client_ip).as_bytes().to_vec().into(),
     };
     msg.write(&mut stdout).await?;
+    // dual-stack: ask the exit for an IPv6 address over our own signaling
+    // RelConn (the same pattern as rekeying/hole-punching) rather than
+    // growing the out-of-tree `vpn_structs::Message` handshake, so this
+    // doesn't depend on a crate bump landing first. An exit that doesn't
+    // speak this yet just lets the conn open fail or the request time out,
+    // and we carry on v4-only. This is verb 2, not another verb 1, so the
+    // control-channel consumer can tell it apart from the IPv4 interface
+    // config instead of treating it as a duplicate/one-shot event.
+    let client_ipv6 = negotiate_ipv6_async(&mux)
+        .timeout(Duration::from_secs(3))
+        .await
+        .and_then(Result::ok)
+        .flatten();
+    if let Some(client_ipv6) = client_ipv6 {
+        log::info!("negotiated IPv6 address {}!", client_ipv6);
+        let msg = StdioMsg {
+            verb: 2,
+            body: format!("{}/64", client_ipv6).as_bytes().to_vec().into(),
+        };
+        msg.write(&mut stdout).await?;
+    }
     stdout.flush().await?;
 
     let vpn_up_fut = {
         let mux = mux.clone();
         let stats = stats.clone();
         async move {
-            let ack_rate_limits: Vec<_> = (0..16)
-                .map(|_| {
-                    governor::RateLimiter::direct(Quota::per_second(
-                        NonZeroU32::new(500u32).unwrap(),
-                    ))
-                })
-                .collect();
+            let shaper = QosShaper::new(qos);
 
             loop {
                 let msg = StdioMsg::read(&mut stdin).await?;
-                // ACK decimation
-                if let Some(hash) = ack_decimate(&msg.body) {
-                    let limiter = &(ack_rate_limits[(hash % 16) as usize]);
-                    if limiter.check().is_err() {
-                        continue;
-                    }
+                if !shaper.admit(&msg.body).await {
+                    continue;
                 }
                 stats.incr_total_tx(msg.body.len() as u64);
                 drop(
@@ -391,7 +1072,7 @@ async fn run_vpn(
         async move {
             for count in 0u64.. {
                 if count % 1000 == 0 {
-                    let sess_stats = mux.get_session().get_stats().await;
+                    let sess_stats = mux.get_stats().await;
                     log::debug!(
                     "VPN received {} pkts; ping {} ms; loss = {:.2}% => {:.2}%; overhead = {:.2}%",
                     count,
@@ -415,15 +1096,254 @@ async fn run_vpn(
     smol::future::race(GEXEC.spawn(vpn_up_fut), GEXEC.spawn(vpn_down_fut)).await
 }
 
-/// returns ok if it's an ack that needs to be decimated
-fn ack_decimate(bts: &[u8]) -> Option<u16> {
-    let parsed = Ipv4Packet::new(bts)?;
-    let parsed = TcpPacket::new(parsed.payload())?;
-    let flags = parsed.get_flags();
-    if flags & TcpFlags::ACK != 0 && flags & TcpFlags::SYN == 0 && parsed.payload().is_empty() {
-        let hash = parsed.get_destination() ^ parsed.get_source();
-        Some(hash)
-    } else {
-        None
+/// A direct (unkeyed) governor rate limiter -- the type `RateLimiter::direct`
+/// actually returns.
+type Limiter = governor::RateLimiter<
+    governor::state::NotKeyed,
+    governor::state::InMemoryState,
+    governor::clock::DefaultClock,
+>;
+
+/// Which shaping class an outbound packet falls into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum TrafficClass {
+    /// small packets other than bare ACKs -- likely interactive traffic --
+    /// bypass shaping entirely.
+    Interactive,
+    /// bare TCP ACKs, thinned out per-flow to relieve upstream pressure on
+    /// asymmetric links.
+    Ack,
+    /// everything else: gets a fair-share rate limit per flow.
+    Bulk,
+}
+
+/// The 5-tuple identifying a flow, used as the shaping bucket key so ACK
+/// decimation (and bulk shaping) is per-flow instead of colliding across
+/// unrelated flows the way the old `src ^ dst` hash could.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey {
+    src: IpAddr,
+    dst: IpAddr,
+    src_port: u16,
+    dst_port: u16,
+    proto: u8,
+}
+
+/// Tunables for the outbound QoS classifier, configurable rather than
+/// compile-time constants so users on very asymmetric links can tune upstream
+/// ACK pressure.
+#[derive(Clone, Debug)]
+pub struct QosConfig {
+    /// packets no larger than this bypass shaping entirely as "interactive",
+    /// except bare TCP ACKs, which always fall in the `Ack` class.
+    pub interactive_max_bytes: usize,
+    /// per-flow rate limit applied to the `Bulk` class.
+    pub bulk_quota: Quota,
+    /// per-flow rate limit applied to the `Ack` class.
+    pub ack_quota: Quota,
+}
+
+impl Default for QosConfig {
+    fn default() -> Self {
+        QosConfig {
+            interactive_max_bytes: 128,
+            bulk_quota: Quota::per_second(NonZeroU32::new(2000).unwrap()),
+            ack_quota: Quota::per_second(NonZeroU32::new(500).unwrap()),
+        }
+    }
+}
+
+/// Classifies a raw outbound packet (v4 or v6) into a flow + traffic class,
+/// or `None` if it's not a TCP packet we know how to classify -- those are
+/// let through unshaped. Dispatches on the actual IP version nibble rather
+/// than trying `Ipv4Packet::new` first, since that only bounds-checks length
+/// and would happily "parse" a v6 packet as a malformed v4 one.
+fn classify_packet(bts: &[u8], config: &QosConfig) -> Option<(FlowKey, TrafficClass)> {
+    match bts.first()? >> 4 {
+        4 => {
+            let v4 = Ipv4Packet::new(bts)?;
+            classify_tcp(
+                IpAddr::V4(v4.get_source()),
+                IpAddr::V4(v4.get_destination()),
+                v4.get_next_level_protocol(),
+                v4.payload(),
+                bts.len(),
+                config,
+            )
+        }
+        6 => {
+            let v6 = Ipv6Packet::new(bts)?;
+            classify_tcp(
+                IpAddr::V6(v6.get_source()),
+                IpAddr::V6(v6.get_destination()),
+                v6.get_next_header(),
+                v6.payload(),
+                bts.len(),
+                config,
+            )
+        }
+        _ => None,
+    }
+}
+
+fn classify_tcp(
+    src: IpAddr,
+    dst: IpAddr,
+    proto: pnet_packet::ip::IpNextHeaderProtocol,
+    payload: &[u8],
+    packet_len: usize,
+    config: &QosConfig,
+) -> Option<(FlowKey, TrafficClass)> {
+    if proto != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    let tcp = TcpPacket::new(payload)?;
+    let key = FlowKey {
+        src,
+        dst,
+        src_port: tcp.get_source(),
+        dst_port: tcp.get_destination(),
+        proto: proto.0,
+    };
+    let flags = tcp.get_flags();
+    let is_bare_ack =
+        flags & TcpFlags::ACK != 0 && flags & TcpFlags::SYN == 0 && tcp.payload().is_empty();
+    if is_bare_ack {
+        return Some((key, TrafficClass::Ack));
+    }
+    if packet_len <= config.interactive_max_bytes {
+        return Some((key, TrafficClass::Interactive));
+    }
+    Some((key, TrafficClass::Bulk))
+}
+
+/// How long a flow's shaping bucket sticks around after its last packet
+/// before it's considered stale and swept, so a long-lived VPN session
+/// doesn't accumulate one bucket per flow it's ever seen.
+const BUCKET_TTL: Duration = Duration::from_secs(120);
+/// How often (in admitted packets) we sweep stale buckets. A counter rather
+/// than a background task, so the shaper doesn't need its own timer loop.
+const BUCKET_SWEEP_EVERY: u64 = 4096;
+
+/// A pluggable traffic-shaping/QoS layer for outbound VPN packets: classifies
+/// each packet into a flow + `TrafficClass`, then shapes classes other than
+/// `Interactive` with their own per-flow governor bucket. Replaces the old
+/// single-purpose, IPv4-only ACK decimator.
+struct QosShaper {
+    config: QosConfig,
+    buckets: smol::lock::Mutex<HashMap<(FlowKey, TrafficClass), (Arc<Limiter>, Instant)>>,
+    admits_since_sweep: AtomicU64,
+}
+
+impl QosShaper {
+    fn new(config: QosConfig) -> Self {
+        QosShaper {
+            config,
+            buckets: smol::lock::Mutex::new(HashMap::new()),
+            admits_since_sweep: AtomicU64::new(0),
+        }
+    }
+
+    /// returns `true` if the packet should be sent now.
+    async fn admit(&self, bts: &[u8]) -> bool {
+        let (key, class) = match classify_packet(bts, &self.config) {
+            Some(v) => v,
+            None => return true,
+        };
+        let quota = match class {
+            TrafficClass::Interactive => return true,
+            TrafficClass::Bulk => self.config.bulk_quota,
+            TrafficClass::Ack => self.config.ack_quota,
+        };
+        let mut buckets = self.buckets.lock().await;
+        let now = Instant::now();
+        let limiter = match buckets.entry((key, class)) {
+            std::collections::hash_map::Entry::Occupied(mut e) => {
+                e.get_mut().1 = now;
+                e.get().0.clone()
+            }
+            std::collections::hash_map::Entry::Vacant(e) => {
+                e.insert((Arc::new(Limiter::direct(quota)), now)).0.clone()
+            }
+        };
+        if self.admits_since_sweep.fetch_add(1, Ordering::Relaxed) >= BUCKET_SWEEP_EVERY {
+            self.admits_since_sweep.store(0, Ordering::Relaxed);
+            buckets.retain(|_, (_, last_used)| now.duration_since(*last_used) < BUCKET_TTL);
+        }
+        limiter.check().is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jittered_backoff_stays_within_bounds() {
+        let base = Duration::from_millis(1000);
+        assert_eq!(
+            jittered_backoff(base, 0.25, 0.0),
+            Duration::from_millis(750)
+        );
+        assert_eq!(
+            jittered_backoff(base, 0.25, 1.0),
+            Duration::from_millis(1250)
+        );
+        assert_eq!(jittered_backoff(base, 0.25, 0.5), base);
+    }
+
+    #[test]
+    fn pick_next_alive_skips_dead_and_wraps() {
+        let alive = [true, false, true, false];
+        assert_eq!(pick_next_alive(&alive, 1), Some(2));
+        assert_eq!(pick_next_alive(&alive, 3), Some(0));
+        assert_eq!(pick_next_alive(&alive, 0), Some(0));
+        assert_eq!(pick_next_alive(&[false, false], 0), None);
+    }
+
+    fn build_v4_tcp(sport: u16, dport: u16, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let total_len = 20 + 20 + payload.len();
+        let mut bts = vec![0u8; total_len];
+        bts[0] = 0x45; // version 4, ihl 5
+        bts[2] = (total_len >> 8) as u8;
+        bts[3] = total_len as u8;
+        bts[9] = 6; // protocol: tcp
+        let tcp = &mut bts[20..];
+        tcp[0] = (sport >> 8) as u8;
+        tcp[1] = sport as u8;
+        tcp[2] = (dport >> 8) as u8;
+        tcp[3] = dport as u8;
+        tcp[12] = 0x50; // data offset: 5 words
+        tcp[13] = flags;
+        tcp[20..].copy_from_slice(payload);
+        bts
+    }
+
+    #[test]
+    fn classify_tcp_bare_ack_is_ack_class() {
+        let config = QosConfig::default();
+        let pkt = build_v4_tcp(1111, 2222, TcpFlags::ACK as u8, &[]);
+        assert_eq!(classify_packet(&pkt, &config).unwrap().1, TrafficClass::Ack);
+    }
+
+    #[test]
+    fn classify_tcp_small_payload_is_interactive() {
+        let config = QosConfig::default();
+        let pkt = build_v4_tcp(1111, 2222, TcpFlags::ACK as u8, b"hello");
+        assert_eq!(
+            classify_packet(&pkt, &config).unwrap().1,
+            TrafficClass::Interactive
+        );
+    }
+
+    #[test]
+    fn classify_tcp_large_payload_is_bulk() {
+        let config = QosConfig::default();
+        let payload = vec![0u8; config.interactive_max_bytes + 100];
+        let pkt = build_v4_tcp(1111, 2222, TcpFlags::ACK as u8, &payload);
+        assert_eq!(
+            classify_packet(&pkt, &config).unwrap().1,
+            TrafficClass::Bulk
+        );
     }
 }